@@ -4,14 +4,195 @@ use crate::{
     synth::Synth,
 };
 
+use std::ops::Range;
+
 use liquid_rust_mir::Rvalue;
-use liquid_rust_ty::{BaseTy, BinOp, Predicate, Ty, UnOp, Variable};
+use liquid_rust_ty::{BaseTy, BinOp, IntSize, Literal, Predicate, Sign, Ty, UnOp, Variable};
+
+/// Returns the inclusive `(MIN, MAX)` range representable by an integer of the given `sign` and
+/// `size`, mirroring the bounds rustc uses in `overflowing_binary_op`.
+///
+/// Returns `None` when the range can't be represented by the `i128` that backs `Literal::Int`:
+/// a 128-bit unsigned integer's `MAX` is `2^128 - 1`, which is more than double `i128::MAX`.
+fn int_bounds(sign: Sign, size: IntSize) -> Option<(i128, i128)> {
+    let bits = size.bits();
+    match sign {
+        // `u128::MAX` doesn't fit in an `i128`; there's no bound we can hand to the solver that
+        // wouldn't misrepresent the actual range, so refuse instead of asserting a wrong one.
+        Sign::Unsigned if bits >= 128 => None,
+        Sign::Unsigned => Some((0, (1i128 << bits) - 1)),
+        // `1i128 << 127` is `i128::MIN`'s bit pattern, and negating `i128::MIN` overflows, so
+        // special-case the widest signed size instead of computing `MIN` through a shift+negate.
+        Sign::Signed if bits >= 128 => Some((i128::MIN, i128::MAX)),
+        Sign::Signed => Some((-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)),
+    }
+}
+
+/// Builds the `op2 != 0` obligation that a `Div`/`Rem`'s divisor must discharge.
+fn nonzero_obligation(ty: BaseTy, divisor: Predicate) -> Predicate {
+    Predicate::BinaryOp(
+        BinOp::Neq(ty),
+        Box::new(divisor),
+        Box::new(Predicate::Lit(Literal::Int(0))),
+    )
+}
+
+/// Builds the `0 <= amount <= max_shift` obligation that a shift's right-hand side must
+/// discharge, tagged with the right-hand side's own `sign`/`size`.
+fn shift_range_obligation(
+    sign: Sign,
+    size: IntSize,
+    amount: Predicate,
+    max_shift: i128,
+) -> Predicate {
+    Predicate::BinaryOp(
+        BinOp::And,
+        Box::new(Predicate::BinaryOp(
+            BinOp::Lte(sign, size),
+            Box::new(Predicate::Lit(Literal::Int(0))),
+            Box::new(amount.clone()),
+        )),
+        Box::new(Predicate::BinaryOp(
+            BinOp::Lte(sign, size),
+            Box::new(amount),
+            Box::new(Predicate::Lit(Literal::Int(max_shift))),
+        )),
+    )
+}
+
+/// Builds the `min <= result <= max` obligation that an overflow-checked arithmetic operation's
+/// result must discharge.
+fn overflow_range_obligation(
+    sign: Sign,
+    size: IntSize,
+    result: Predicate,
+    min: i128,
+    max: i128,
+) -> Predicate {
+    Predicate::BinaryOp(
+        BinOp::And,
+        Box::new(Predicate::BinaryOp(
+            BinOp::Lte(sign, size),
+            Box::new(Predicate::Lit(Literal::Int(min))),
+            Box::new(result.clone()),
+        )),
+        Box::new(Predicate::BinaryOp(
+            BinOp::Lte(sign, size),
+            Box::new(result),
+            Box::new(Predicate::Lit(Literal::Int(max))),
+        )),
+    )
+}
+
+/// Substitutes every occurrence of `var` in `predicate` with `replacement`.
+///
+/// This is how a user-supplied operator refinement's formal parameters get tied to the actual
+/// operands of a `BinApp`/`UnApp` once they have been resolved into predicates.
+fn subst(predicate: &Predicate, var: &Variable, replacement: &Predicate) -> Predicate {
+    match predicate {
+        Predicate::Var(v) if v == var => replacement.clone(),
+        Predicate::Var(_) | Predicate::Lit(_) => predicate.clone(),
+        Predicate::BinaryOp(bin_op, lhs, rhs) => Predicate::BinaryOp(
+            *bin_op,
+            Box::new(subst(lhs, var, replacement)),
+            Box::new(subst(rhs, var, replacement)),
+        ),
+        Predicate::UnaryOp(un_op, op) => {
+            Predicate::UnaryOp(*un_op, Box::new(subst(op, var, replacement)))
+        }
+    }
+}
+
+/// Checks `op1`/`op2` against a user-supplied operator refinement `sig` for `bin_op` and, if they
+/// satisfy it, synthesizes the instantiated result type.
+///
+/// `sig` is the `Ty::Func` a function signature in the surface syntax can attach to an operator
+/// that is not one of the builtin `BaseTy::Int`/`Bool` operations (e.g. an operator overloaded
+/// for a refined newtype). This lets refinement checking follow the same contract a user wrote
+/// down instead of assuming the builtin `{ b | b == op1 bin_op op2 }` contract.
+fn synth_overloaded_binop(
+    env: &Env,
+    sig: &Ty,
+    bin_op: BinOp,
+    op1_ty: &Ty,
+    op2_ty: &Ty,
+    op1: Predicate,
+    op2: Predicate,
+    span1: Range<usize>,
+    span2: Range<usize>,
+) -> TyResult<Range<usize>, Ty> {
+    let call_span = span1.start..span2.end;
+
+    let Ty::Func(params, ret) = sig else {
+        // A non-function refinement can't be the contract of an operator call.
+        return Err(TyError {
+            kind: TyErrorKind::NotAFunction { found: sig.clone() },
+            span: call_span,
+        });
+    };
+    let [(var1, param1), (var2, param2)] = &params[..] else {
+        return Err(TyError {
+            kind: TyErrorKind::ArityMismatch {
+                expected: 2,
+                found: params.len(),
+            },
+            span: call_span,
+        });
+    };
+
+    for (formal_ty, actual_ty, actual_span) in
+        [(param1, op1_ty, span1.clone()), (param2, op2_ty, span2.clone())]
+    {
+        if !actual_ty.has_base(formal_ty.base()) {
+            return Err(TyError {
+                kind: TyErrorKind::BaseMismatch {
+                    expected: formal_ty.base(),
+                    found: actual_ty.clone(),
+                },
+                span: actual_span,
+            });
+        }
+    }
+
+    // Check the operands against each of the operator's parameter refinements, substituting the
+    // actual operands for the formal parameters. This is what lets an overloaded `/`-like
+    // operator whose divisor parameter carries `{ v | v != 0 }` reject a zero divisor, the same
+    // way the builtin `Div`/`Rem` obligation does.
+    for (param, operand, param_span) in [(param1, &op1, span1.clone()), (param2, &op2, span2.clone())] {
+        if let Ty::Refined(_, pre) = param {
+            // Inside a parameter's refinement, the bound variable stands for that parameter's own
+            // operand.
+            let pre = subst(pre, &Variable::Bound, operand);
+            let pre = subst(&pre, var2, &op2);
+            let pre = subst(&pre, var1, &op1);
+            if !env.check_obligation(&pre) {
+                return Err(TyError {
+                    kind: TyErrorKind::OperatorPreconditionNotMet { op: bin_op },
+                    span: param_span,
+                });
+            }
+        }
+    }
 
-impl<'env> Synth<'env, ()> for Rvalue {
+    // Instantiate the operator's postcondition with the actual operands to get the refined
+    // result type.
+    let ret_ty = match ret.as_ref() {
+        Ty::Refined(base, post) => {
+            let post = subst(post, var2, &op2);
+            let post = subst(&post, var1, &op1);
+            Ty::Refined(*base, post)
+        }
+        other => other.clone(),
+    };
+
+    Ok(ret_ty)
+}
+
+impl<'env> Synth<'env, Range<usize>> for Rvalue {
     type Ty = Ty;
     type Envs = &'env Env;
 
-    fn synth(&self, env: Self::Envs) -> TyResult<(), Self::Ty> {
+    fn synth(&self, env: Self::Envs) -> TyResult<Range<usize>, Self::Ty> {
         match self {
             Rvalue::Use(operand) => operand.synth(env),
             Rvalue::UnApp(un_op, op) => {
@@ -33,7 +214,7 @@ impl<'env> Synth<'env, ()> for Rvalue {
                             expected: param_ty,
                             found: op_ty1.clone(),
                         },
-                        span: (),
+                        span: op.span(),
                     });
                 }
 
@@ -58,6 +239,20 @@ impl<'env> Synth<'env, ()> for Rvalue {
                     | BinOp::Rem(sign, size) => {
                         (BaseTy::Int(*sign, *size), BaseTy::Int(*sign, *size))
                     }
+                    // Bitwise operators receive two integers of the same type and return an
+                    // integer of the same type.
+                    BinOp::BitAnd(sign, size)
+                    | BinOp::BitOr(sign, size)
+                    | BinOp::BitXor(sign, size) => {
+                        (BaseTy::Int(*sign, *size), BaseTy::Int(*sign, *size))
+                    }
+                    // Shift operators receive an integer on the left-hand side and return an
+                    // integer of the same type. The right-hand side is handled separately below:
+                    // it is an independent integer type and is not required to have the same
+                    // shape as the left-hand side.
+                    BinOp::Shl(sign, size) | BinOp::Shr(sign, size) => {
+                        (BaseTy::Int(*sign, *size), BaseTy::Int(*sign, *size))
+                    }
                     // Rust's MIR does not have boolean binary operators. They are here just to be
                     // reused in predicates.
                     BinOp::And | BinOp::Or => unreachable!(),
@@ -75,27 +270,76 @@ impl<'env> Synth<'env, ()> for Rvalue {
                 let op_ty1 = op1.synth(env)?;
                 let op_ty2 = op2.synth(env)?;
 
-                // The type of the operands should be the same.
-                //
-                // FIXME: this is not the case for the offset and shift operators.
-                if !op_ty1.shape_eq(&op_ty2) {
+                // Keep the spans of the operands around: once they are resolved into predicates
+                // below, the `Operand`s are gone, but the spans are still needed to point at the
+                // offending operand of any obligation that fails to discharge.
+                let span1 = op1.span();
+                let span2 = op2.span();
+
+                let is_shift = matches!(bin_op, BinOp::Shl(..) | BinOp::Shr(..));
+
+                // The type of the operands should be the same, except for shift operators: their
+                // right-hand side is an independent integer type (e.g. `u32 << u8` is valid,
+                // unlike every other binary operator). Operands that don't fit that builtin shape
+                // (e.g. `Meters * f64`, two independently-typed operands of an overloaded
+                // operator) might still support the operator if the user attached a refinement to
+                // it; try that before reporting a mismatch.
+                if !is_shift && !op_ty1.shape_eq(&op_ty2) {
+                    if let Some(sig) = env.operator_sig(*bin_op) {
+                        return synth_overloaded_binop(
+                            env,
+                            sig,
+                            *bin_op,
+                            &op_ty1,
+                            &op_ty2,
+                            env.resolve_operand(op1),
+                            env.resolve_operand(op2),
+                            span1.clone(),
+                            span2.clone(),
+                        );
+                    }
                     return Err(TyError {
                         kind: TyErrorKind::ShapeMismatch {
                             expected: op_ty1.clone(),
                             found: op_ty2.clone(),
                         },
-                        span: (),
+                        span: span2.clone(),
                     });
                 }
                 // The type of the operands must have the type that the operator receives as base
-                // type.
+                // type. Same as above: fall back to a user-supplied operator refinement before
+                // reporting a mismatch.
                 if !op_ty1.has_base(op_ty) {
+                    if let Some(sig) = env.operator_sig(*bin_op) {
+                        return synth_overloaded_binop(
+                            env,
+                            sig,
+                            *bin_op,
+                            &op_ty1,
+                            &op_ty2,
+                            env.resolve_operand(op1),
+                            env.resolve_operand(op2),
+                            span1.clone(),
+                            span2.clone(),
+                        );
+                    }
                     return Err(TyError {
                         kind: TyErrorKind::BaseMismatch {
                             expected: op_ty,
                             found: op_ty1.clone(),
                         },
-                        span: (),
+                        span: span1.clone(),
+                    });
+                }
+                // For shifts, the right-hand side only needs to be some integer type, not one
+                // with the same shape as the left-hand side.
+                if is_shift && !matches!(op_ty2.base(), BaseTy::Int(_, _)) {
+                    return Err(TyError {
+                        kind: TyErrorKind::BaseMismatch {
+                            expected: op_ty,
+                            found: op_ty2.clone(),
+                        },
+                        span: span2.clone(),
                     });
                 }
 
@@ -104,6 +348,71 @@ impl<'env> Synth<'env, ()> for Rvalue {
                 let op1 = Box::new(env.resolve_operand(op1));
                 let op2 = Box::new(env.resolve_operand(op2));
 
+                // `Div` and `Rem` are undefined when the divisor is zero, so we require the
+                // environment to prove `op2 != 0` under the current path condition before we
+                // can synthesize a type for the operation.
+                if let BinOp::Div(sign, size) | BinOp::Rem(sign, size) = bin_op {
+                    let ty = BaseTy::Int(*sign, *size);
+                    let nonzero = nonzero_obligation(ty, (*op2).clone());
+                    if !env.check_obligation(&nonzero) {
+                        return Err(TyError {
+                            kind: TyErrorKind::DivisionByZero,
+                            span: span2.clone(),
+                        });
+                    }
+                }
+
+                // Shifting by an amount greater than or equal to the bit width of the left-hand
+                // side is undefined, so we require the environment to prove the shift amount is
+                // in range, mirroring rustc's shift-overflow check. The comparison is tagged
+                // with the right-hand side's own (independent) integer type, since that's the
+                // type of the value actually being compared.
+                if let BinOp::Shl(_, size) | BinOp::Shr(_, size) = bin_op {
+                    let (rhs_sign, rhs_size) = match op_ty2.base() {
+                        BaseTy::Int(sign, size) => (sign, size),
+                        _ => unreachable!("checked above that the shift amount is an integer"),
+                    };
+                    let max_shift = size.bits() as i128 - 1;
+                    let in_range =
+                        shift_range_obligation(rhs_sign, rhs_size, (*op2).clone(), max_shift);
+                    if !env.check_obligation(&in_range) {
+                        return Err(TyError {
+                            kind: TyErrorKind::ShiftAmountOutOfRange,
+                            span: span2.clone(),
+                        });
+                    }
+                }
+
+                // When overflow checks are enabled, `Add`/`Sub`/`Mul` must additionally prove
+                // that the mathematical result of the operation fits in `ret_ty`, matching the
+                // panic rustc inserts for these operators in `binop_with_overflow`.
+                if env.overflow_checks() {
+                    if let BinOp::Add(sign, size) | BinOp::Sub(sign, size) | BinOp::Mul(sign, size) =
+                        bin_op
+                    {
+                        // A 128-bit unsigned range can't be represented by the `i128` that backs
+                        // `Literal::Int`; report that explicitly instead of checking against a
+                        // bound that would misrepresent the actual range.
+                        let Some((min, max)) = int_bounds(*sign, *size) else {
+                            return Err(TyError {
+                                kind: TyErrorKind::UnsupportedOverflowCheck { ty: ret_ty },
+                                span: span1.start..span2.end,
+                            });
+                        };
+                        let result = Predicate::BinaryOp(*bin_op, op1.clone(), op2.clone());
+                        let in_bounds = overflow_range_obligation(*sign, *size, result, min, max);
+                        if !env.check_obligation(&in_bounds) {
+                            return Err(TyError {
+                                kind: TyErrorKind::ArithmeticOverflow {
+                                    op: *bin_op,
+                                    ty: ret_ty,
+                                },
+                                span: span1.start..span2.end,
+                            });
+                        }
+                    }
+                }
+
                 // Return the `{ b : B | b == (op1 bin_op op2) }` type.
                 Ok(Ty::Refined(
                     ret_ty,
@@ -113,4 +422,118 @@ impl<'env> Synth<'env, ()> for Rvalue {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{int_bounds, nonzero_obligation, overflow_range_obligation, shift_range_obligation};
+    use liquid_rust_ty::{BaseTy, BinOp, IntSize, Literal, Predicate, Sign};
+
+    const SIZES: [IntSize; 5] = [
+        IntSize::Size8,
+        IntSize::Size16,
+        IntSize::Size32,
+        IntSize::Size64,
+        IntSize::Size128,
+    ];
+
+    #[test]
+    fn unsigned_bounds_start_at_zero_and_dont_panic() {
+        for size in SIZES {
+            let (min, max) = int_bounds(Sign::Unsigned, size).unwrap_or((0, i128::MAX));
+            assert_eq!(min, 0);
+            assert!(max >= 0);
+        }
+    }
+
+    #[test]
+    fn unsigned_128_bit_is_unrepresentable() {
+        // `u128::MAX` doesn't fit in the `i128` that backs `Literal::Int`.
+        assert_eq!(int_bounds(Sign::Unsigned, IntSize::Size128), None);
+    }
+
+    #[test]
+    fn signed_bounds_are_twos_complement_and_dont_panic() {
+        for size in SIZES {
+            let (min, max) = int_bounds(Sign::Signed, size).unwrap();
+            assert_eq!(min, -max - 1);
+        }
+    }
+
+    #[test]
+    fn signed_128_bit_does_not_overflow() {
+        // Regression test: computing `MIN` as `-(1i128 << 127)` panics with "attempt to negate
+        // with overflow" because `1i128 << 127` is `i128::MIN`'s bit pattern.
+        assert_eq!(
+            int_bounds(Sign::Signed, IntSize::Size128),
+            Some((i128::MIN, i128::MAX))
+        );
+    }
+
+    #[test]
+    fn signed_32_bit_matches_i32_range() {
+        assert_eq!(
+            int_bounds(Sign::Signed, IntSize::Size32),
+            Some((i32::MIN as i128, i32::MAX as i128))
+        );
+    }
+
+    #[test]
+    fn unsigned_32_bit_matches_u32_range() {
+        assert_eq!(
+            int_bounds(Sign::Unsigned, IntSize::Size32),
+            Some((0, u32::MAX as i128))
+        );
+    }
+
+    #[test]
+    fn nonzero_obligation_compares_the_divisor_against_zero() {
+        let divisor = Predicate::Lit(Literal::Int(0));
+        let obligation = nonzero_obligation(BaseTy::Int(Sign::Signed, IntSize::Size32), divisor);
+        assert!(matches!(
+            obligation,
+            Predicate::BinaryOp(BinOp::Neq(BaseTy::Int(Sign::Signed, IntSize::Size32)), _, rhs)
+                if matches!(*rhs, Predicate::Lit(Literal::Int(0)))
+        ));
+    }
+
+    #[test]
+    fn shift_range_obligation_bounds_the_amount_by_max_shift() {
+        let amount = Predicate::Lit(Literal::Int(3));
+        let obligation =
+            shift_range_obligation(Sign::Unsigned, IntSize::Size8, amount, 7);
+        let Predicate::BinaryOp(BinOp::And, lower, upper) = obligation else {
+            panic!("expected a conjunction of two bounds");
+        };
+        assert!(matches!(
+            *lower,
+            Predicate::BinaryOp(BinOp::Lte(Sign::Unsigned, IntSize::Size8), lo, _)
+                if matches!(*lo, Predicate::Lit(Literal::Int(0)))
+        ));
+        assert!(matches!(
+            *upper,
+            Predicate::BinaryOp(BinOp::Lte(Sign::Unsigned, IntSize::Size8), _, hi)
+                if matches!(*hi, Predicate::Lit(Literal::Int(7)))
+        ));
+    }
+
+    #[test]
+    fn overflow_range_obligation_bounds_the_result_by_min_and_max() {
+        let result = Predicate::Lit(Literal::Int(42));
+        let obligation =
+            overflow_range_obligation(Sign::Signed, IntSize::Size32, result, -8, 8);
+        let Predicate::BinaryOp(BinOp::And, lower, upper) = obligation else {
+            panic!("expected a conjunction of two bounds");
+        };
+        assert!(matches!(
+            *lower,
+            Predicate::BinaryOp(BinOp::Lte(Sign::Signed, IntSize::Size32), lo, _)
+                if matches!(*lo, Predicate::Lit(Literal::Int(-8)))
+        ));
+        assert!(matches!(
+            *upper,
+            Predicate::BinaryOp(BinOp::Lte(Sign::Signed, IntSize::Size32), _, hi)
+                if matches!(*hi, Predicate::Lit(Literal::Int(8)))
+        ));
+    }
+}